@@ -1,19 +1,252 @@
 #![warn(clippy::all, clippy::pedantic)]
 
 use std::fs;
+use zed_extension_api::settings::LspSettings;
 use zed_extension_api::LanguageServerId;
 use zed_extension_api::{self as zed, Result};
 
+const SERVER_NAME: &str = "crates-lsp";
+const GITHUB_REPO: &str = "MathiasPius/crates-lsp";
+
+/// Subdirectory, owned entirely by this extension, that installed versions live under.
+/// Cleanup only ever touches entries inside this namespace, never siblings in the
+/// extension's working directory.
+const EXTENSION_DIR: &str = "crates-lsp";
+const VERSION_DIR_PREFIX: &str = "crates-lsp-";
+
+/// Marker file dropped inside a version directory that was installed to satisfy a pinned
+/// `version` setting. Its presence is what protects the directory from `prune_stale_versions`,
+/// since a pin from one worktree must survive cleanup triggered by any other worktree.
+const PINNED_MARKER_FILE: &str = ".pinned";
+
 struct CratesLSPExtension {
     cached_binary_path: Option<String>,
 }
 
+/// Settings keys consumed by the extension itself (binary install/version resolution) rather
+/// than meant for crates-lsp's `initializationOptions` — kept out of whatever gets forwarded
+/// there by [`CratesLSPExtension::language_server_initialization_options`].
+const VERSION_SETTING_KEY: &str = "version";
+const PRE_RELEASE_SETTING_KEY: &str = "pre_release";
+const EXTENSION_SETTINGS_KEYS: &[&str] = &[VERSION_SETTING_KEY, PRE_RELEASE_SETTING_KEY];
+
+/// Version-related settings read from the `crates-lsp` LSP settings block.
+struct VersionSettings {
+    /// A specific release tag to install, e.g. `"0.5.0"`, pinned instead of always
+    /// resolving the latest release.
+    version: Option<String>,
+    /// Whether pre-release versions are eligible when resolving the latest release.
+    /// Ignored when `version` is set.
+    pre_release: bool,
+}
+
+impl VersionSettings {
+    fn for_worktree(worktree: &zed::Worktree) -> Self {
+        let settings = LspSettings::for_worktree(SERVER_NAME, worktree)
+            .ok()
+            .and_then(|lsp_settings| lsp_settings.settings);
+
+        let version = settings
+            .as_ref()
+            .and_then(|settings| settings.get(VERSION_SETTING_KEY))
+            .and_then(|version| version.as_str())
+            .map(str::to_owned);
+
+        let pre_release = settings
+            .as_ref()
+            .and_then(|settings| settings.get(PRE_RELEASE_SETTING_KEY))
+            .and_then(zed::serde_json::Value::as_bool)
+            .unwrap_or(false);
+
+        Self {
+            version,
+            pre_release,
+        }
+    }
+}
+
+/// Builds the list of asset names worth trying for `platform`/`arch`, in priority order,
+/// alongside the archive format each one was published in.
+///
+/// Apple Silicon additionally tries the `x86_64` Darwin asset as a fallback, since it can
+/// run under Rosetta when no native `aarch64` build is published.
+fn candidate_assets(
+    platform: zed::Os,
+    arch: zed::Architecture,
+) -> Vec<(String, zed::DownloadedFileType)> {
+    let arch_names: &[&str] = match (platform, arch) {
+        (zed::Os::Mac, zed::Architecture::Aarch64) => &["aarch64", "x86_64"],
+        (_, zed::Architecture::Aarch64) => &["aarch64"],
+        (_, zed::Architecture::X86) => &["x86"],
+        (_, zed::Architecture::X8664) => &["x86_64"],
+    };
+
+    let os_exts: &[(&str, zed::DownloadedFileType)] = match platform {
+        zed::Os::Mac => &[
+            ("apple-darwin.tar.gz", zed::DownloadedFileType::GzipTar),
+            ("apple-darwin.tar.xz", zed::DownloadedFileType::XzTar),
+        ],
+        zed::Os::Linux => &[
+            ("unknown-linux-gnu.tar.gz", zed::DownloadedFileType::GzipTar),
+            ("unknown-linux-gnu.tar.xz", zed::DownloadedFileType::XzTar),
+        ],
+        zed::Os::Windows => &[("pc-windows-msvc.zip", zed::DownloadedFileType::Zip)],
+    };
+
+    arch_names
+        .iter()
+        .flat_map(|arch_name| {
+            os_exts.iter().map(move |(os_ext, file_type)| {
+                (format!("crates-lsp-{arch_name}-{os_ext}"), *file_type)
+            })
+        })
+        .collect()
+}
+
+/// Overlays `overrides` onto `base`, replacing the value of any key present in both and
+/// appending keys unique to `overrides`. Used to let a user-configured `env` in the
+/// `crates-lsp` LSP settings block augment (rather than replace) the worktree's shell env.
+fn merge_env(
+    base: Vec<(String, String)>,
+    overrides: Vec<(String, String)>,
+) -> Vec<(String, String)> {
+    let mut merged = base;
+
+    for (key, value) in overrides {
+        let existing = merged
+            .iter_mut()
+            .find(|(existing_key, _)| *existing_key == key);
+
+        if let Some(existing) = existing {
+            existing.1 = value;
+        } else {
+            merged.push((key, value));
+        }
+    }
+
+    merged
+}
+
+/// Marks `install_dir` as holding a pinned version, protecting it from `prune_stale_versions`
+/// regardless of which worktree's settings next trigger a cleanup pass.
+fn mark_pinned(install_dir: &str) -> Result<()> {
+    fs::write(format!("{install_dir}/{PINNED_MARKER_FILE}"), b"")
+        .map_err(|err| format!("failed to write pin marker in '{install_dir}': {err}"))
+}
+
+/// Removes stale `crates-lsp-*` version directories inside `extension_dir`, keeping only
+/// `keep_version_dir` and any directory carrying a [`PINNED_MARKER_FILE`] (installed to satisfy
+/// a pin from some worktree, possibly not this one). Entries that don't match the
+/// version-directory naming convention are left untouched, so this is safe to run against a
+/// directory that also holds unrelated files.
+fn prune_stale_versions(extension_dir: &str, keep_version_dir: &str) -> Result<()> {
+    let entries = fs::read_dir(extension_dir)
+        .map_err(|err| format!("failed to list directory '{extension_dir}': {err}"))?;
+
+    for entry in entries {
+        let entry = entry.map_err(|err| format!("failed to load directory entry: {err}"))?;
+
+        let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
+            continue;
+        };
+
+        if !name.starts_with(VERSION_DIR_PREFIX) || name == keep_version_dir {
+            continue;
+        }
+
+        if entry.path().join(PINNED_MARKER_FILE).exists() {
+            continue;
+        }
+
+        fs::remove_dir_all(entry.path()).ok();
+    }
+
+    Ok(())
+}
+
+/// Where to download the `crates-lsp` archive from once a version has been decided on.
+enum DownloadSource {
+    /// A single asset already confirmed to exist on the release (the "latest" path, where the
+    /// release's full asset list is fetched up front).
+    Resolved {
+        url: String,
+        file_type: zed::DownloadedFileType,
+    },
+    /// A pinned version whose asset list is never fetched, so each candidate name has to be
+    /// tried against GitHub's predictable release-asset URL, in priority order, until one
+    /// actually downloads.
+    Pinned {
+        version: String,
+        candidates: Vec<(String, zed::DownloadedFileType)>,
+    },
+}
+
+/// Decides which version of `crates-lsp` to install for `worktree` and where to get it from: a
+/// pinned version (tried against each candidate asset name at download time, since its asset
+/// list is never fetched) or the latest release (whose asset list is checked against
+/// `candidates` up front).
+fn resolve_download_source(
+    worktree: &zed::Worktree,
+    candidates: Vec<(String, zed::DownloadedFileType)>,
+) -> Result<(String, DownloadSource)> {
+    let VersionSettings {
+        version,
+        pre_release,
+    } = VersionSettings::for_worktree(worktree);
+
+    if let Some(version) = version {
+        return Ok((
+            version.clone(),
+            DownloadSource::Pinned {
+                version,
+                candidates,
+            },
+        ));
+    }
+
+    let release = zed::latest_github_release(
+        GITHUB_REPO,
+        zed::GithubReleaseOptions {
+            require_assets: true,
+            pre_release,
+        },
+    )?;
+
+    let (asset, file_type) = candidates
+        .iter()
+        .find_map(|(name, file_type)| {
+            release
+                .assets
+                .iter()
+                .find(|asset| &asset.name == name)
+                .map(|asset| (asset, *file_type))
+        })
+        .ok_or_else(|| {
+            let tried = candidates
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("no asset found matching any of: {tried}")
+        })?;
+
+    Ok((
+        release.version,
+        DownloadSource::Resolved {
+            url: asset.download_url.clone(),
+            file_type,
+        },
+    ))
+}
+
 impl CratesLSPExtension {
     /// Returns the path to the `crates-lsp` binary. If the binary is not found, it will download
-    /// the latest version from GitHub and extract it to the current working directory.
+    /// the requested (or latest) version from GitHub and extract it to a per-version directory
+    /// under [`EXTENSION_DIR`].
     fn language_server_binary_path(
         &mut self,
         language_server_id: &LanguageServerId,
+        worktree: &zed::Worktree,
     ) -> Result<String> {
         if let Some(path) = &self.cached_binary_path {
             if fs::metadata(path).is_ok_and(|stat| stat.is_file()) {
@@ -26,73 +259,56 @@ impl CratesLSPExtension {
             &zed::LanguageServerInstallationStatus::CheckingForUpdate,
         );
 
-        let release = zed::latest_github_release(
-            "MathiasPius/crates-lsp",
-            zed::GithubReleaseOptions {
-                require_assets: true,
-                pre_release: false,
-            },
-        )?;
-
         let (platform, arch) = zed::current_platform();
+        let candidates = candidate_assets(platform, arch);
 
-        let asset_name = format!(
-            "crates-lsp-{arch}-{os}",
-            arch = match arch {
-                zed::Architecture::Aarch64 => "aarch64",
-                zed::Architecture::X86 => "x86",
-                zed::Architecture::X8664 => "x86_64",
-            },
-            os = match platform {
-                zed::Os::Mac => "apple-darwin.tar.gz",
-                zed::Os::Linux => "unknown-linux-gnu.tar.gz",
-                zed::Os::Windows => "pc-windows-msvc.zip",
-            },
-        );
-
-        let asset = release
-            .assets
-            .iter()
-            .find(|asset| asset.name == asset_name)
-            .ok_or_else(|| format!("no asset found matching {asset_name:?}"))?;
+        let (version, source) = resolve_download_source(worktree, candidates)?;
+        let was_pinned = matches!(&source, DownloadSource::Pinned { .. });
 
-        let version_dir = format!("crates-lsp-{}", release.version);
+        let version_dir = format!("{VERSION_DIR_PREFIX}{version}");
+        let install_dir = format!("{EXTENSION_DIR}/{version_dir}");
 
-        fs::create_dir_all(&version_dir)
-            .map_err(|err| format!("failed to create directory '{version_dir}': {err}"))?;
+        fs::create_dir_all(&install_dir)
+            .map_err(|err| format!("failed to create directory '{install_dir}': {err}"))?;
 
         let binary_path = format!(
-            "{version_dir}/{bin_name}",
+            "{install_dir}/{bin_name}",
             bin_name = match platform {
                 zed::Os::Windows => "crates-lsp.exe",
                 zed::Os::Mac | zed::Os::Linux => "crates-lsp",
             }
         );
 
-        let file_type = match platform {
-            zed::Os::Windows => zed::DownloadedFileType::Zip,
-            zed::Os::Mac | zed::Os::Linux => zed::DownloadedFileType::GzipTar,
-        };
-
         if !fs::metadata(&binary_path).is_ok_and(|stat| stat.is_file()) {
             zed::set_language_server_installation_status(
                 language_server_id,
                 &zed::LanguageServerInstallationStatus::Downloading,
             );
 
-            zed::download_file(&asset.download_url, &version_dir, file_type)
-                .map_err(|err| format!("failed to download file: {err}"))?;
+            match source {
+                DownloadSource::Resolved { url, file_type } => {
+                    zed::download_file(&url, &install_dir, file_type).map_err(|err| {
+                        format!("failed to download '{url}' to '{install_dir}': {err}")
+                    })?;
+                }
+                DownloadSource::Pinned {
+                    version,
+                    candidates,
+                } => {
+                    download_first_matching_candidate(&version, &candidates, &install_dir)?;
+                }
+            }
 
             zed::make_file_executable(&binary_path)?;
 
-            let entries = fs::read_dir(".")
-                .map_err(|err| format!("failed to list working directory {err}"))?;
-            for entry in entries {
-                let entry = entry.map_err(|err| format!("failed to load directory entry {err}"))?;
-                if entry.file_name().to_str() != Some(&version_dir) {
-                    fs::remove_dir_all(entry.path()).ok();
-                }
+            // Record the pin with a marker file rather than just skipping cleanup here: the
+            // worktree that resolves "latest" (or a different pin) runs its own cleanup pass
+            // later, and that pass has no other way to know this directory must survive it.
+            if was_pinned {
+                mark_pinned(&install_dir)?;
             }
+
+            prune_stale_versions(EXTENSION_DIR, &version_dir)?;
         }
 
         self.cached_binary_path = Some(binary_path.clone());
@@ -101,6 +317,33 @@ impl CratesLSPExtension {
     }
 }
 
+/// Tries each candidate asset name for `version` against GitHub's predictable release-asset
+/// URL, in priority order, until one downloads successfully. Returns an error listing every
+/// URL attempted if none of them exist.
+fn download_first_matching_candidate(
+    version: &str,
+    candidates: &[(String, zed::DownloadedFileType)],
+    install_dir: &str,
+) -> Result<()> {
+    let mut attempted = Vec::with_capacity(candidates.len());
+
+    for (asset_name, file_type) in candidates {
+        let url = format!(
+            "https://github.com/{GITHUB_REPO}/releases/download/v{version}/{asset_name}"
+        );
+
+        match zed::download_file(&url, install_dir, *file_type) {
+            Ok(()) => return Ok(()),
+            Err(err) => attempted.push(format!("{url} ({err})")),
+        }
+    }
+
+    Err(format!(
+        "failed to download any matching asset for version {version}, tried: {}",
+        attempted.join(", ")
+    ))
+}
+
 impl zed::Extension for CratesLSPExtension {
     fn new() -> Self {
         Self {
@@ -111,14 +354,185 @@ impl zed::Extension for CratesLSPExtension {
     fn language_server_command(
         &mut self,
         language_server_id: &LanguageServerId,
-        _worktree: &zed::Worktree,
+        worktree: &zed::Worktree,
     ) -> Result<zed::Command> {
+        let binary_settings = LspSettings::for_worktree(SERVER_NAME, worktree)
+            .ok()
+            .and_then(|lsp_settings| lsp_settings.binary);
+
+        let path = binary_settings
+            .as_ref()
+            .and_then(|binary| binary.path.clone())
+            .or_else(|| worktree.which(SERVER_NAME))
+            .map(Ok)
+            .unwrap_or_else(|| self.language_server_binary_path(language_server_id, worktree))?;
+
+        let args = binary_settings
+            .as_ref()
+            .and_then(|binary| binary.arguments.clone())
+            .unwrap_or_default();
+
+        let env = merge_env(
+            worktree.shell_env(),
+            binary_settings
+                .and_then(|binary| binary.env)
+                .unwrap_or_default(),
+        );
+
         Ok(zed::Command {
-            command: self.language_server_binary_path(language_server_id)?,
-            args: Vec::default(),
-            env: Vec::default(),
+            command: path,
+            args,
+            env,
         })
     }
+
+    fn language_server_initialization_options(
+        &mut self,
+        _language_server_id: &LanguageServerId,
+        worktree: &zed::Worktree,
+    ) -> Result<Option<zed::serde_json::Value>> {
+        let mut settings = LspSettings::for_worktree(SERVER_NAME, worktree)
+            .ok()
+            .and_then(|lsp_settings| lsp_settings.settings)
+            .unwrap_or_default();
+
+        // The settings block is shared with VersionSettings, which reads the extension's own
+        // version-pinning keys out of it; don't leak those into crates-lsp's init payload.
+        if let zed::serde_json::Value::Object(settings) = &mut settings {
+            for key in EXTENSION_SETTINGS_KEYS {
+                settings.remove(*key);
+            }
+        }
+
+        Ok(Some(settings))
+    }
 }
 
 zed::register_extension!(CratesLSPExtension);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    /// Creates a fresh, uniquely-named directory under the OS temp dir for a single test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!(
+            "crates-lsp-test-{name}-{}-{nanos}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn candidate_assets_prefers_native_arch_over_rosetta_fallback() {
+        let candidates = candidate_assets(zed::Os::Mac, zed::Architecture::Aarch64);
+
+        assert_eq!(
+            candidates.first().map(|(name, _)| name.as_str()),
+            Some("crates-lsp-aarch64-apple-darwin.tar.gz")
+        );
+        assert!(candidates
+            .iter()
+            .any(|(name, _)| name == "crates-lsp-x86_64-apple-darwin.tar.gz"));
+    }
+
+    #[test]
+    fn candidate_assets_has_no_rosetta_fallback_off_apple_silicon() {
+        let candidates = candidate_assets(zed::Os::Linux, zed::Architecture::Aarch64);
+
+        assert!(candidates
+            .iter()
+            .all(|(name, _)| name.starts_with("crates-lsp-aarch64-")));
+    }
+
+    #[test]
+    fn candidate_assets_includes_tar_xz_variant() {
+        let candidates = candidate_assets(zed::Os::Linux, zed::Architecture::X8664);
+
+        assert!(candidates.iter().any(|(name, file_type)| name
+            == "crates-lsp-x86_64-unknown-linux-gnu.tar.xz"
+            && matches!(file_type, zed::DownloadedFileType::XzTar)));
+    }
+
+    #[test]
+    fn merge_env_overrides_existing_key() {
+        let merged = merge_env(
+            vec![("PATH".to_owned(), "/usr/bin".to_owned())],
+            vec![("PATH".to_owned(), "/custom/bin".to_owned())],
+        );
+
+        assert_eq!(
+            merged,
+            vec![("PATH".to_owned(), "/custom/bin".to_owned())]
+        );
+    }
+
+    #[test]
+    fn merge_env_appends_new_key() {
+        let merged = merge_env(
+            vec![("PATH".to_owned(), "/usr/bin".to_owned())],
+            vec![("CRATES_REGISTRY".to_owned(), "https://example.com".to_owned())],
+        );
+
+        assert_eq!(
+            merged,
+            vec![
+                ("PATH".to_owned(), "/usr/bin".to_owned()),
+                ("CRATES_REGISTRY".to_owned(), "https://example.com".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn prune_stale_versions_removes_version_dirs_other_than_kept() {
+        let dir = temp_dir("prune-versions");
+
+        fs::create_dir_all(dir.join("crates-lsp-0.1.0")).unwrap();
+        fs::create_dir_all(dir.join("crates-lsp-0.2.0")).unwrap();
+
+        prune_stale_versions(dir.to_str().unwrap(), "crates-lsp-0.2.0").unwrap();
+
+        assert!(!dir.join("crates-lsp-0.1.0").exists());
+        assert!(dir.join("crates-lsp-0.2.0").exists());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn prune_stale_versions_leaves_pinned_version_dirs_alone() {
+        let dir = temp_dir("prune-pinned");
+
+        fs::create_dir_all(dir.join("crates-lsp-0.1.0")).unwrap();
+        fs::write(dir.join("crates-lsp-0.1.0").join(PINNED_MARKER_FILE), b"").unwrap();
+        fs::create_dir_all(dir.join("crates-lsp-0.3.0")).unwrap();
+
+        prune_stale_versions(dir.to_str().unwrap(), "crates-lsp-0.3.0").unwrap();
+
+        assert!(dir.join("crates-lsp-0.1.0").exists());
+        assert!(dir.join("crates-lsp-0.3.0").exists());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn prune_stale_versions_leaves_unrelated_entries_alone() {
+        let dir = temp_dir("prune-unrelated");
+
+        fs::create_dir_all(dir.join("not-a-version-dir")).unwrap();
+        fs::write(dir.join("readme.txt"), b"keep me").unwrap();
+
+        prune_stale_versions(dir.to_str().unwrap(), "crates-lsp-0.2.0").unwrap();
+
+        assert!(dir.join("not-a-version-dir").exists());
+        assert!(dir.join("readme.txt").exists());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}